@@ -0,0 +1,76 @@
+//! An `async` mirror of [`ser::to_clmr`](super::ser::to_clmr), for pushing a
+//! message straight into an async muxrpc/boxstream sink without buffering
+//! the whole encoding into an owned `Vec<u8>` first. Gated behind the
+//! `async` feature so the `futures` dependency stays optional.
+
+use futures::io::{AsyncWrite, AsyncWriteExt};
+use serde::Serialize;
+use ssb_legacy_msg_data::cbor;
+use varu64;
+
+use super::super::{Content, Message};
+use super::ser::{CompactEncode, EncodeClmrError};
+
+/// Serialize a `Message` into an `AsyncWrite`, using the same
+/// flags/author/sequence/timestamp/previous/content/signature layout as
+/// [`to_clmr`](super::ser::to_clmr), routed through the same
+/// [`CompactEncode`] impls so the two encoders can't drift apart. The flags
+/// byte and the timestamp are written as they are; every other field is
+/// first encoded into a reusable scratch buffer (`CompactEncode`/the CBOR
+/// encoder only target the blocking `std::io::Write`) and then pushed with a
+/// single `write_all`.
+pub async fn to_clmr_async<W, T>(msg: &Message<T>, w: &mut W) -> Result<(), EncodeClmrError>
+    where W: AsyncWrite + Unpin,
+          T: Serialize
+{
+    let mut scratch = Vec::new();
+
+    let mut flags = 0u8;
+    if msg.previous.is_some() {
+        flags |= 0b0000_0100;
+    }
+    if msg.swapped {
+        flags |= 0b0000_0010;
+    }
+    if msg.is_encrypted() {
+        flags |= 0b0000_0001;
+    }
+    w.write_all(&[flags]).await?;
+
+    scratch.clear();
+    msg.author.encode(&mut scratch)?;
+    w.write_all(&scratch).await?;
+
+    scratch.clear();
+    varu64::encode_write(msg.sequence, &mut scratch)?;
+    w.write_all(&scratch).await?;
+
+    let timestamp: [u8; 8] =
+        unsafe { std::mem::transmute(u64::to_be(f64::to_bits(msg.timestamp.into()))) };
+    w.write_all(&timestamp).await?;
+
+    if let Some(ref mh) = msg.previous {
+        scratch.clear();
+        mh.encode(&mut scratch)?;
+        w.write_all(&scratch).await?;
+    }
+
+    match msg.content {
+        Content::Encrypted(ref mb) => {
+            scratch.clear();
+            mb.encode(&mut scratch)?;
+            w.write_all(&scratch).await?;
+        }
+        Content::Plain(ref t) => {
+            scratch.clear();
+            cbor::to_writer(&mut scratch, t)?;
+            w.write_all(&scratch).await?;
+        }
+    }
+
+    scratch.clear();
+    msg.signature.encode(&mut scratch)?;
+    w.write_all(&scratch).await?;
+
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use std::io::{self, Read};
+
+use serde::de::DeserializeOwned;
+use ssb_legacy_msg_data::cbor;
+use varu64;
+
+use super::super::{Content, Message};
+use super::ser::SIGNATURE_COMPACT_LEN;
+
+/// Everything that can go wrong when decoding a `Message` from clmr.
+#[derive(Debug)]
+pub enum DecodeClmrError {
+    /// An io error occured on the underlying reader.
+    Io(io::Error),
+    /// Deserializing the plaintext content errored.
+    Content(cbor::DecodeCborError),
+    /// A compact-encoded field (the length prefix, author, previous,
+    /// encrypted content box or signature) failed to decode. Carries the
+    /// debug representation of the underlying error, since those types
+    /// don't share a common error type with this crate.
+    Compact(String),
+}
+
+impl From<io::Error> for DecodeClmrError {
+    fn from(e: io::Error) -> DecodeClmrError {
+        DecodeClmrError::Io(e)
+    }
+}
+
+impl From<cbor::DecodeCborError> for DecodeClmrError {
+    fn from(e: cbor::DecodeCborError) -> DecodeClmrError {
+        DecodeClmrError::Content(e)
+    }
+}
+
+/// Deserialize a `Message` that was written with
+/// [`to_clmr_framed`](super::ser::to_clmr_framed): read the `varu64` byte
+/// length prefix, take exactly that many bytes, and decode every field from
+/// that one bounded buffer.
+///
+/// Plain content has no explicit length of its own in the clmr layout, so
+/// handing a reader straight to the CBOR decoder risks it over-reading into
+/// the signature that follows — the same greedy-reader problem the framing
+/// is meant to solve, just one field down. Since the signature is always
+/// exactly `SIGNATURE_COMPACT_LEN` bytes, the content's length can instead
+/// be derived by subtracting it from what's left of the frame, and decoded
+/// from that exact sub-slice, which the CBOR decoder cannot read past.
+pub fn from_clmr_framed<R, T>(r: &mut R) -> Result<Message<T>, DecodeClmrError>
+    where R: Read,
+          T: DeserializeOwned
+{
+    let frame_len = varu64::decode_read(&mut *r)
+        .map_err(|(e, _)| DecodeClmrError::Compact(format!("{:?}", e)))?;
+
+    let mut body = vec![0u8; frame_len as usize];
+    r.read_exact(&mut body)?;
+    let mut cursor: &[u8] = &body[..];
+
+    let mut flags = [0u8; 1];
+    cursor.read_exact(&mut flags)?;
+    let flags = flags[0];
+    let has_previous = flags & 0b0000_0100 != 0;
+    let swapped = flags & 0b0000_0010 != 0;
+    let is_encrypted = flags & 0b0000_0001 != 0;
+
+    let author = super::super::FeedId::from_compact(&mut cursor)
+        .map_err(|e| DecodeClmrError::Compact(format!("{:?}", e)))?;
+
+    let sequence = varu64::decode_read(&mut cursor)
+        .map_err(|(e, _)| DecodeClmrError::Compact(format!("{:?}", e)))?;
+
+    let mut timestamp_bytes = [0u8; 8];
+    cursor.read_exact(&mut timestamp_bytes)?;
+    let timestamp = f64::from_bits(u64::from_be(unsafe { std::mem::transmute(timestamp_bytes) }));
+
+    let previous = if has_previous {
+        Some(super::super::MessageId::from_compact(&mut cursor)
+            .map_err(|e| DecodeClmrError::Compact(format!("{:?}", e)))?)
+    } else {
+        None
+    };
+
+    let content = if is_encrypted {
+        Content::Encrypted(super::super::EncryptedContent::from_compact(&mut cursor)
+            .map_err(|e| DecodeClmrError::Compact(format!("{:?}", e)))?)
+    } else {
+        let (content_bytes, rest) = split_content_and_signature(cursor)?;
+        cursor = rest;
+        Content::Plain(cbor::from_slice(content_bytes)?)
+    };
+
+    let signature = super::super::Signature::from_compact(&mut cursor)
+        .map_err(|e| DecodeClmrError::Compact(format!("{:?}", e)))?;
+
+    Ok(Message {
+        author,
+        sequence,
+        timestamp: timestamp.into(),
+        previous,
+        swapped,
+        content,
+        signature,
+    })
+}
+
+/// Carve the trailing `SIGNATURE_COMPACT_LEN` bytes (the signature) off of
+/// what's left of a frame after the flags/author/sequence/timestamp/
+/// previous fields have been consumed, handing back the bytes in between as
+/// the plain content. Kept as its own function so the arithmetic that
+/// prevents the CBOR decoder from reading into the signature can be tested
+/// without needing a full `Message`.
+fn split_content_and_signature(rest: &[u8]) -> Result<(&[u8], &[u8]), DecodeClmrError> {
+    let content_len = rest.len().checked_sub(SIGNATURE_COMPACT_LEN)
+        .ok_or_else(|| DecodeClmrError::Compact("frame too short for a signature".to_string()))?;
+    Ok(rest.split_at(content_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::ser::to_clmr_framed;
+
+    fn test_feed_id() -> super::super::super::FeedId {
+        super::super::super::FeedId::from_compact(&mut &[0u8; 32][..]).unwrap()
+    }
+
+    fn test_message_id() -> super::super::super::MessageId {
+        super::super::super::MessageId::from_compact(&mut &[0u8; 32][..]).unwrap()
+    }
+
+    fn test_signature() -> super::super::super::Signature {
+        super::super::super::Signature::from_compact(&mut &[0u8; SIGNATURE_COMPACT_LEN][..]).unwrap()
+    }
+
+    fn test_encrypted_content() -> super::super::super::EncryptedContent {
+        let mut buf = Vec::new();
+        varu64::encode_write(4, &mut buf).unwrap();
+        buf.extend_from_slice(&[1, 2, 3, 4]);
+        super::super::super::EncryptedContent::from_compact(&mut &buf[..]).unwrap()
+    }
+
+    fn test_message(content: Content<Vec<u8>>, previous: Option<super::super::super::MessageId>) -> Message<Vec<u8>> {
+        Message {
+            author: test_feed_id(),
+            sequence: 1,
+            timestamp: 0.0.into(),
+            previous,
+            swapped: false,
+            content,
+            signature: test_signature(),
+        }
+    }
+
+    #[test]
+    fn frames_and_unframes_a_real_plain_message() {
+        let msg = test_message(Content::Plain(vec![1u8, 2, 3, 4, 5]), None);
+
+        let mut buf = Vec::new();
+        to_clmr_framed(&msg, &mut buf).unwrap();
+
+        let mut cursor: &[u8] = &buf[..];
+        let decoded: Message<Vec<u8>> = from_clmr_framed(&mut cursor).unwrap();
+
+        assert_eq!(decoded.sequence, msg.sequence);
+        assert_eq!(decoded.swapped, msg.swapped);
+        assert!(decoded.previous.is_none());
+        match decoded.content {
+            Content::Plain(v) => assert_eq!(v, vec![1u8, 2, 3, 4, 5]),
+            Content::Encrypted(_) => panic!("expected Content::Plain"),
+        }
+        assert!(cursor.is_empty());
+    }
+
+    #[test]
+    fn frames_and_unframes_a_real_encrypted_message_with_a_previous() {
+        let msg = test_message(
+            Content::Encrypted(test_encrypted_content()),
+            Some(test_message_id()),
+        );
+
+        let mut buf = Vec::new();
+        to_clmr_framed(&msg, &mut buf).unwrap();
+
+        let mut cursor: &[u8] = &buf[..];
+        let decoded: Message<Vec<u8>> = from_clmr_framed(&mut cursor).unwrap();
+
+        assert_eq!(decoded.sequence, msg.sequence);
+        assert!(decoded.previous.is_some());
+        match decoded.content {
+            Content::Encrypted(_) => {}
+            Content::Plain(_) => panic!("expected Content::Encrypted"),
+        }
+        assert!(cursor.is_empty());
+    }
+
+    #[test]
+    fn splits_the_signature_off_the_end_of_the_remaining_frame() {
+        let mut rest = vec![0xAAu8; 10];
+        rest.extend(vec![0xBBu8; SIGNATURE_COMPACT_LEN]);
+
+        let (content, signature) = split_content_and_signature(&rest).unwrap();
+
+        assert_eq!(content, &vec![0xAAu8; 10][..]);
+        assert_eq!(signature, &vec![0xBBu8; SIGNATURE_COMPACT_LEN][..]);
+    }
+
+    #[test]
+    fn errors_when_the_remaining_frame_is_shorter_than_a_signature() {
+        let rest = vec![0u8; SIGNATURE_COMPACT_LEN - 1];
+        assert!(split_content_and_signature(&rest).is_err());
+    }
+
+    #[test]
+    fn concatenated_frames_do_not_bleed_into_each_other() {
+        let mut buf = Vec::new();
+        varu64::encode_write(5, &mut buf).unwrap();
+        buf.extend_from_slice(b"hello");
+        varu64::encode_write(5, &mut buf).unwrap();
+        buf.extend_from_slice(b"world");
+
+        let mut cursor: &[u8] = &buf[..];
+
+        let len1 = varu64::decode_read(&mut cursor).unwrap();
+        let mut body1 = vec![0u8; len1 as usize];
+        cursor.read_exact(&mut body1).unwrap();
+        assert_eq!(&body1, b"hello");
+
+        let len2 = varu64::decode_read(&mut cursor).unwrap();
+        let mut body2 = vec![0u8; len2 as usize];
+        cursor.read_exact(&mut body2).unwrap();
+        assert_eq!(&body2, b"world");
+
+        assert!(cursor.is_empty());
+    }
+}
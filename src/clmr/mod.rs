@@ -0,0 +1,9 @@
+//! The [clmr](https://spec.scuttlebutt.nz/messages.html#compact-legacy-message-representation)
+//! (compact legacy message representation) wire format: a dense binary
+//! encoding of a legacy `Message`, used alongside the canonical JSON form.
+
+#[cfg(feature = "async")]
+pub mod async_ser;
+pub mod de;
+pub mod private_box;
+pub mod ser;
@@ -0,0 +1,256 @@
+//! A [private-box](https://ssbc.github.io/scuttlebutt-protocol-guide/#private-messages)-style
+//! scheme, turning a `Content::Plain<T>` into the `Content::Encrypted` blob
+//! that [`to_clmr`](super::ser::to_clmr) already knows how to serialize, and
+//! back again.
+//!
+//! For each message, a random nonce and an ephemeral curve25519 key pair are
+//! generated, and a fresh random message key is used to seal the body. The
+//! message key is then sealed once per recipient (up to
+//! [`MAX_RECIPIENTS`]), so only holders of a listed recipient's secret key
+//! can recover it and decrypt the body.
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sodiumoxide::crypto::{box_, secretbox};
+use ssb_legacy_msg_data::cbor;
+
+use super::super::{Content, EncryptedContent};
+
+const MESSAGE_KEY_LEN: usize = 32;
+
+/// The largest number of recipients a single private-box can be sealed to.
+/// This mirrors the private-box spec's own recipient limit, not a byte-width
+/// constraint on the key box's recipient-count field (a `u8` could count far
+/// higher than this).
+pub const MAX_RECIPIENTS: usize = 7;
+
+/// Everything that can go wrong when sealing or opening a private-box.
+#[derive(Debug)]
+pub enum PrivateBoxError {
+    /// The plaintext content failed to serialize to CBOR.
+    EncodeContent(cbor::EncodeCborError),
+    /// The decrypted body failed to deserialize as CBOR.
+    DecodeContent(cbor::DecodeCborError),
+    /// The blob was too short or otherwise malformed to contain a nonce, an
+    /// ephemeral key and at least one sealed message key. `encrypt` also
+    /// returns this when handed content that is already `Content::Encrypted`,
+    /// or when a recipient key yields no shared secret.
+    Malformed,
+    /// More than [`MAX_RECIPIENTS`] recipients were given to `encrypt`.
+    TooManyRecipients,
+    /// `encrypt` was called with no recipients at all, which would produce a
+    /// blob nobody could ever open.
+    NoRecipients,
+    /// None of the listed key slots could be opened with the given secret
+    /// key, or the sealed body failed to authenticate.
+    NotForUs,
+}
+
+impl From<cbor::EncodeCborError> for PrivateBoxError {
+    fn from(e: cbor::EncodeCborError) -> PrivateBoxError {
+        PrivateBoxError::EncodeContent(e)
+    }
+}
+
+impl From<cbor::DecodeCborError> for PrivateBoxError {
+    fn from(e: cbor::DecodeCborError) -> PrivateBoxError {
+        PrivateBoxError::DecodeContent(e)
+    }
+}
+
+/// Seal `content` so that only the holders of the secret keys matching
+/// `recipients` (at most [`MAX_RECIPIENTS`]) can read it, producing a
+/// `Content::Encrypted` ready to be handed to `to_clmr`.
+///
+/// Layout: `nonce ‖ ephemeral public key ‖ one sealed key box per recipient
+/// ‖ sealed body`. Each key box seals a one-byte recipient count followed by
+/// the random message key, using a secretbox keyed on
+/// `sha256(scalarmult(ephemeral secret key, recipient public key))`; the
+/// body is the CBOR-encoded plaintext, secretboxed under the message key.
+/// The same freshly-generated nonce is reused for every box in the message,
+/// which is safe because it is never reused across messages.
+pub fn encrypt<T: Serialize>(
+    content: &Content<T>,
+    recipients: &[box_::PublicKey],
+) -> Result<EncryptedContent, PrivateBoxError> {
+    if recipients.is_empty() {
+        return Err(PrivateBoxError::NoRecipients);
+    }
+    if recipients.len() > MAX_RECIPIENTS {
+        return Err(PrivateBoxError::TooManyRecipients);
+    }
+
+    let plaintext = match content {
+        Content::Plain(ref t) => cbor::to_vec(t)?,
+        Content::Encrypted(_) => return Err(PrivateBoxError::Malformed),
+    };
+
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+
+    let mut message_key = [0u8; MESSAGE_KEY_LEN];
+    OsRng.fill_bytes(&mut message_key);
+
+    let mut nonce_bytes = [0u8; secretbox::NONCEBYTES];
+    OsRng.fill_bytes(&mut nonce_bytes);
+    let nonce = secretbox::Nonce::from_slice(&nonce_bytes)
+        .expect("a freshly-generated NONCEBYTES array is a valid nonce");
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&nonce_bytes);
+    out.extend_from_slice(ephemeral_pk.as_ref());
+
+    for recipient in recipients {
+        let shared_secret = scalarmult(&ephemeral_sk, recipient)
+            .map_err(|_| PrivateBoxError::Malformed)?;
+        let key = secretbox::Key::from_slice(&Sha256::digest(&shared_secret))
+            .expect("sha256 output is the right length for a secretbox key");
+
+        let mut slot = Vec::with_capacity(1 + MESSAGE_KEY_LEN);
+        slot.push(recipients.len() as u8);
+        slot.extend_from_slice(&message_key);
+
+        out.extend_from_slice(&secretbox::seal(&slot, &nonce, &key));
+    }
+
+    let body_key = secretbox::Key::from_slice(&message_key)
+        .expect("message key is the right length for a secretbox key");
+    out.extend_from_slice(&secretbox::seal(&plaintext, &nonce, &body_key));
+
+    Ok(EncryptedContent::from(out))
+}
+
+/// Try to open an `EncryptedContent` blob with `our_secret_key`, trying each
+/// key slot in turn until one opens (or none do).
+pub fn decrypt<T: DeserializeOwned>(
+    blob: &EncryptedContent,
+    our_secret_key: &box_::SecretKey,
+) -> Result<Content<T>, PrivateBoxError> {
+    let bytes: &[u8] = blob.as_ref();
+
+    const SLOT_LEN: usize = 1 + MESSAGE_KEY_LEN + secretbox::MACBYTES;
+
+    if bytes.len() < secretbox::NONCEBYTES + box_::PUBLICKEYBYTES + SLOT_LEN {
+        return Err(PrivateBoxError::Malformed);
+    }
+    let (nonce_bytes, rest) = bytes.split_at(secretbox::NONCEBYTES);
+    let nonce = secretbox::Nonce::from_slice(nonce_bytes).ok_or(PrivateBoxError::Malformed)?;
+
+    let (ephemeral_pk_bytes, rest) = rest.split_at(box_::PUBLICKEYBYTES);
+    let ephemeral_pk =
+        box_::PublicKey::from_slice(ephemeral_pk_bytes).ok_or(PrivateBoxError::Malformed)?;
+
+    let shared_secret =
+        scalarmult(our_secret_key, &ephemeral_pk).map_err(|_| PrivateBoxError::NotForUs)?;
+    let key = secretbox::Key::from_slice(&Sha256::digest(&shared_secret))
+        .expect("sha256 output is the right length for a secretbox key");
+
+    let mut offset = 0;
+    while offset + SLOT_LEN <= rest.len() {
+        let slot = &rest[offset..offset + SLOT_LEN];
+        if let Ok(opened) = secretbox::open(slot, &nonce, &key) {
+            let recipient_count = opened[0] as usize;
+            let message_key = &opened[1..1 + MESSAGE_KEY_LEN];
+
+            let body_offset = recipient_count * SLOT_LEN;
+            if body_offset > rest.len() {
+                return Err(PrivateBoxError::Malformed);
+            }
+            let sealed_body = &rest[body_offset..];
+
+            let body_key = secretbox::Key::from_slice(message_key)
+                .expect("message key is the right length for a secretbox key");
+            let plaintext = secretbox::open(sealed_body, &nonce, &body_key)
+                .map_err(|_| PrivateBoxError::NotForUs)?;
+
+            let t = cbor::from_slice(&plaintext)?;
+            return Ok(Content::Plain(t));
+        }
+        offset += SLOT_LEN;
+    }
+
+    Err(PrivateBoxError::NotForUs)
+}
+
+/// Diffie-Hellman the two curve25519 keys into a shared secret. `pk` may come
+/// straight off the wire (the ephemeral key embedded in a blob `decrypt` is
+/// trying to open), so low-order or otherwise degenerate points are reported
+/// as an error rather than `.expect()`-ed into a panic: a crafted blob must
+/// not be able to crash a reader.
+fn scalarmult(sk: &box_::SecretKey, pk: &box_::PublicKey) -> Result<Vec<u8>, ()> {
+    use sodiumoxide::crypto::scalarmult::{scalarmult, GroupElement, Scalar};
+
+    let scalar = Scalar::from_slice(sk.as_ref()).expect("secret key has scalar length");
+    let point = GroupElement::from_slice(pk.as_ref()).expect("public key has group element length");
+    Ok(scalarmult(&scalar, &point).map_err(|_| ())?.as_ref().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_encrypt_and_decrypt() {
+        let (pk, sk) = box_::gen_keypair();
+        let content = Content::Plain(vec![1u8, 2, 3]);
+
+        let encrypted = encrypt(&content, &[pk]).unwrap();
+        let decrypted: Content<Vec<u8>> = decrypt(&encrypted, &sk).unwrap();
+
+        match decrypted {
+            Content::Plain(v) => assert_eq!(v, vec![1u8, 2, 3]),
+            Content::Encrypted(_) => panic!("expected Content::Plain"),
+        }
+    }
+
+    #[test]
+    fn round_trips_to_every_one_of_several_recipients() {
+        let recipients: Vec<(box_::PublicKey, box_::SecretKey)> =
+            (0..3).map(|_| box_::gen_keypair()).collect();
+        let public_keys: Vec<box_::PublicKey> =
+            recipients.iter().map(|(pk, _)| pk.clone()).collect();
+
+        let content = Content::Plain("hello".to_string());
+        let encrypted = encrypt(&content, &public_keys).unwrap();
+
+        for (_, sk) in &recipients {
+            let decrypted: Content<String> = decrypt(&encrypted, sk).unwrap();
+            match decrypted {
+                Content::Plain(s) => assert_eq!(s, "hello"),
+                Content::Encrypted(_) => panic!("expected Content::Plain"),
+            }
+        }
+    }
+
+    #[test]
+    fn a_stranger_cannot_decrypt() {
+        let (pk, _) = box_::gen_keypair();
+        let (_, stranger_sk) = box_::gen_keypair();
+
+        let content = Content::Plain(vec![1u8, 2, 3]);
+        let encrypted = encrypt(&content, &[pk]).unwrap();
+
+        let result: Result<Content<Vec<u8>>, _> = decrypt(&encrypted, &stranger_sk);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn refuses_no_recipients() {
+        let content = Content::Plain(vec![1u8]);
+        let result = encrypt(&content, &[]);
+        assert!(matches!(result, Err(PrivateBoxError::NoRecipients)));
+    }
+
+    #[test]
+    fn refuses_more_than_max_recipients() {
+        let public_keys: Vec<box_::PublicKey> = (0..MAX_RECIPIENTS + 1)
+            .map(|_| box_::gen_keypair().0)
+            .collect();
+
+        let content = Content::Plain(vec![1u8]);
+        let result = encrypt(&content, &public_keys);
+        assert!(matches!(result, Err(PrivateBoxError::TooManyRecipients)));
+    }
+}
@@ -6,6 +6,22 @@ use varu64;
 
 use super::super::{Message, Content};
 
+/// A `Write` sink that only tallies how many bytes it would have written,
+/// used to measure a CBOR encoding's length without allocating a buffer for
+/// it.
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 /// Everything that can go wrong when encoding a `Message` to clmr.
 #[derive(Debug)]
 pub enum EncodeClmrError {
@@ -45,45 +61,173 @@ pub fn to_clmr<W, T>(msg: &Message<T>, w: &mut W) -> Result<(), EncodeClmrError>
     }
 
     w.write_all(&[flags])?;
-    // println!("flags: {:x?}", flags);
 
-    msg.author.to_compact(&mut *w)?;
-    // println!("author: {:x?}", msg.author.to_compact_vec());
+    msg.author.encode(&mut *w)?;
 
     varu64::encode_write(msg.sequence, &mut *w)?;
-    // println!("sequence: {:x?}", msg.sequence);
 
     let timestamp: [u8; 8] =
         unsafe { std::mem::transmute(u64::to_be(f64::to_bits(msg.timestamp.into()))) };
     w.write_all(&timestamp)?;
 
     if let Some(ref mh) = msg.previous {
-        let _ = mh.to_compact(&mut *w)?;
-        // println!("previous: {:x?}", mh.to_compact_vec());
+        mh.encode(&mut *w)?;
     }
 
     match msg.content {
         Content::Encrypted(ref mb) => {
-            mb.to_compact(w)?;
-            // println!("encrypted: {:x?}", mb.to_compact_vec());
+            mb.encode(&mut *w)?;
         }
         Content::Plain(ref t) => {
             cbor::to_writer(&mut *w, t)?;
-            // println!("content: {:x?}", cbor::to_vec(t));
         }
     }
 
-    msg.signature.to_compact(w)?;
-    // println!("signature: {:x?}", msg.signature.to_compact_vec());
+    msg.signature.encode(w)?;
 
     Ok(())
 }
 
+/// Compute the exact number of bytes `to_clmr` would write for `msg`,
+/// without serializing it twice: the content length is obtained by running
+/// the CBOR encoder against a byte-counting writer rather than a real one.
+pub fn clmr_len<T: Serialize>(msg: &Message<T>) -> Result<usize, EncodeClmrError> {
+    let mut len = 1; // flags
+
+    len += msg.author.encoded_len();
+    len += varu64::encoding_length(msg.sequence);
+    len += 8; // timestamp
+
+    if let Some(ref mh) = msg.previous {
+        len += mh.encoded_len();
+    }
+
+    len += match msg.content {
+        Content::Encrypted(ref mb) => mb.encoded_len(),
+        Content::Plain(ref t) => {
+            let mut counter = ByteCounter(0);
+            cbor::to_writer(&mut counter, t)?;
+            counter.0
+        }
+    };
+
+    len += msg.signature.encoded_len();
+
+    Ok(len)
+}
+
+/// Something that has a clmr compact encoding: a fixed procedure for
+/// writing itself to a `Write`r plus a way to ask how many bytes that
+/// writing will take, without actually doing it. Unifies the formerly
+/// ad-hoc `to_compact` calls on `author`, `previous`, `content` and
+/// `signature` so downstream crates can encode or size vectors and maps of
+/// feed refs, message refs and blobs the same way.
+pub trait CompactEncode {
+    /// Write this value's compact encoding to `w`.
+    fn encode<W: Write>(&self, w: &mut W) -> Result<(), EncodeClmrError>;
+
+    /// The number of bytes `encode` would write, without writing them.
+    fn encoded_len(&self) -> usize;
+}
+
+impl CompactEncode for super::super::FeedId {
+    fn encode<W: Write>(&self, w: &mut W) -> Result<(), EncodeClmrError> {
+        self.to_compact(w)?;
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.compact_len()
+    }
+}
+
+impl CompactEncode for super::super::MessageId {
+    fn encode<W: Write>(&self, w: &mut W) -> Result<(), EncodeClmrError> {
+        self.to_compact(w)?;
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.compact_len()
+    }
+}
+
+/// The fixed length, in bytes, of a compact-encoded `Signature`: legacy SSB
+/// messages are always signed with ed25519, whose signatures are exactly 64
+/// bytes. Exposed so [`from_clmr_framed`](super::de::from_clmr_framed) can
+/// carve the signature off the end of a frame without guessing at a length
+/// that might drift from what `encode` below actually writes.
+pub(crate) const SIGNATURE_COMPACT_LEN: usize = 64;
+
+impl CompactEncode for super::super::Signature {
+    fn encode<W: Write>(&self, w: &mut W) -> Result<(), EncodeClmrError> {
+        self.to_compact(w)?;
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        SIGNATURE_COMPACT_LEN
+    }
+}
+
+impl CompactEncode for super::super::EncryptedContent {
+    fn encode<W: Write>(&self, w: &mut W) -> Result<(), EncodeClmrError> {
+        self.to_compact(w)?;
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.compact_len()
+    }
+}
+
+/// Serialize a `Message` into a writer, prefixed with a `varu64` byte length
+/// of the clmr body that follows. This makes the encoding self-delimiting:
+/// a reader working through a log or socket of concatenated messages can
+/// take exactly that many bytes for this message without first decoding it,
+/// and without risking an over-eager CBOR reader consuming into the next
+/// frame. See [`from_clmr_framed`](super::de::from_clmr_framed) for the
+/// matching decoder.
+pub fn to_clmr_framed<W, T>(msg: &Message<T>, w: &mut W) -> Result<(), EncodeClmrError>
+    where W: Write,
+          T: Serialize
+{
+    varu64::encode_write(clmr_len(msg)? as u64, &mut *w)?;
+    to_clmr(msg, w)
+}
+
+/// A `Write`r that can pre-reserve space for bytes about to be streamed into
+/// it. Unlike `Write` itself, there is no blanket impl over every writer:
+/// `size_hint` only does something useful for a writer backed by a growable
+/// buffer, so each such writer opts in with its own real reservation logic
+/// instead of inheriting a no-op.
+pub trait Writer: Write {
+    /// Reserve space for `additional` more bytes about to be written.
+    fn size_hint(&mut self, additional: usize);
+}
+
+impl Writer for Vec<u8> {
+    fn size_hint(&mut self, additional: usize) {
+        self.reserve(additional);
+    }
+}
+
+/// Serialize a `Message` into a `Writer`, first calling
+/// [`Writer::size_hint`] with the exact byte count from `clmr_len` so a
+/// buffer-backed writer can pre-reserve before `to_clmr` streams into it.
+pub fn to_clmr_sized<W, T>(msg: &Message<T>, w: &mut W) -> Result<(), EncodeClmrError>
+    where W: Writer,
+          T: Serialize
+{
+    w.size_hint(clmr_len(msg)?);
+    to_clmr(msg, w)
+}
+
 /// Serialize a `Message` into an owned byte vector, using the
 /// [clmr](https://spec.scuttlebutt.nz/messages.html#compact-legacy-message-representation).
 pub fn to_clmr_vec<T: Serialize>(msg: &Message<T>) -> Result<Vec<u8>, EncodeClmrError> {
-    let mut out = Vec::with_capacity(256);
-    to_clmr(msg, &mut out)?;
+    let mut out = Vec::new();
+    to_clmr_sized(msg, &mut out)?;
     Ok(out)
 }
 
@@ -91,4 +235,38 @@ pub fn to_clmr_vec<T: Serialize>(msg: &Message<T>) -> Result<Vec<u8>, EncodeClmr
 /// [clmr](https://spec.scuttlebutt.nz/messages.html#compact-legacy-message-representation).
 pub fn to_clmr_string<T: Serialize>(msg: &Message<T>) -> Result<String, EncodeClmrError> {
     Ok(unsafe { String::from_utf8_unchecked(to_clmr_vec(msg)?) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vec_size_hint_actually_reserves() {
+        let mut out: Vec<u8> = Vec::new();
+        out.size_hint(128);
+        assert!(out.capacity() >= 128);
+    }
+
+    #[test]
+    fn byte_counter_matches_the_length_of_a_real_cbor_encoding() {
+        fn counted_len<T: Serialize>(t: &T) -> usize {
+            let mut counter = ByteCounter(0);
+            cbor::to_writer(&mut counter, t).unwrap();
+            counter.0
+        }
+
+        assert_eq!(counted_len(&0u8), cbor::to_vec(&0u8).unwrap().len());
+        assert_eq!(counted_len(&12345u64), cbor::to_vec(&12345u64).unwrap().len());
+        assert_eq!(
+            counted_len(&vec![1u8, 2, 3, 4, 5]),
+            cbor::to_vec(&vec![1u8, 2, 3, 4, 5]).unwrap().len()
+        );
+        assert_eq!(
+            counted_len(&"a longer string to push past a single-byte cbor length prefix".to_string()),
+            cbor::to_vec(&"a longer string to push past a single-byte cbor length prefix".to_string())
+                .unwrap()
+                .len()
+        );
+    }
 }
\ No newline at end of file